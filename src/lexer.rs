@@ -1,18 +1,48 @@
-extern crate itertools;
-use itertools::Itertools;
-
-use std::str::FromStr;
+use std::char;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LineNumber(pub u32);
 
+/// The type a variable was declared with via its trailing sigil, following
+/// classic BASIC convention: `A$` is a string, `N%` is an integer, and a
+/// bare name like `X` is a (floating-point) number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarKind {
+    Number,
+    Str,
+    Int,
+}
+
+/// A 1-based line / 0-based column location in the source text.
+///
+/// The column is zero at the beginning of a line (BOL=0), so it counts
+/// characters already consumed on the current line rather than a 1-based
+/// "which character is this" count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// The exact range of source text a token (or error) came from, as a
+/// `[start, end)` pair of `Position`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Comment(String),
 
     // Variables and Literals
-    Variable(String),
+    Variable(String, VarKind),
     Number(i32),
+    Float(f64),
     BString(String),
 
     // Binary Operators
@@ -24,8 +54,11 @@ pub enum Token {
     NotEqual,
     Multiply,
     Divide,
+    Mod,
     Minus,
     Plus,
+    And,
+    Or,
 
     // Parens
     LParen,
@@ -34,6 +67,7 @@ pub enum Token {
     // Unary Operators
     Bang,
     UMinus,
+    Not,
 
     // Keywords
     Goto,
@@ -62,6 +96,10 @@ impl Token {
             "(" => Some(Token::LParen),
             ")" => Some(Token::RParen),
             "!" => Some(Token::Bang),
+            "MOD" => Some(Token::Mod),
+            "AND" => Some(Token::And),
+            "OR" => Some(Token::Or),
+            "NOT" => Some(Token::Not),
             "GOTO" => Some(Token::Goto),
             "IF" => Some(Token::If),
             "INPUT" => Some(Token::Input),
@@ -76,33 +114,42 @@ impl Token {
     pub fn is_operator(&self) -> bool {
         match *self {
             Token::Equals | Token::LessThan | Token::GreaterThan | Token::LessThanEqual |
-            Token::NotEqual | Token::Multiply | Token::Divide | Token::Minus | Token::Plus => true,
+            Token::GreaterThanEqual | Token::NotEqual | Token::Multiply | Token::Divide |
+            Token::Mod | Token::Minus | Token::Plus | Token::And | Token::Or => true,
             _ => false,
         }
     }
 
     pub fn is_value(&self) -> bool {
         match *self {
-            Token::Variable(_) |
+            Token::Variable(_, _) |
             Token::Number(_) |
+            Token::Float(_) |
             Token::BString(_) => true,
             _ => false,
         }
     }
 
+    /// Binding strength of a binary operator, highest first: `* / MOD`,
+    /// then `+ -`, then the relational operators, then `AND`, then `OR`
+    /// (lowest). Unary `NOT` isn't a binary operator and so has no entry
+    /// here -- it binds tighter than any of these when the parser applies
+    /// it to its single operand.
     pub fn operator_precedence(&self) -> Result<u8, String> {
         match *self {
-            Token::Multiply | Token::Divide => Ok(10),
+            Token::Multiply | Token::Divide | Token::Mod => Ok(10),
             Token::Minus | Token::Plus => Ok(8),
             Token::Equals | Token::LessThan | Token::GreaterThan | Token::LessThanEqual |
-            Token::NotEqual => Ok(4),
+            Token::GreaterThanEqual | Token::NotEqual => Ok(4),
+            Token::And => Ok(2),
+            Token::Or => Ok(1),
             _ => Err("Not an operator".to_string()),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct TokenAndPos(pub u32, pub Token);
+pub struct TokenAndPos(pub Span, pub Token);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct LineOfCode {
@@ -110,103 +157,553 @@ pub struct LineOfCode {
     pub tokens: Vec<TokenAndPos>,
 }
 
-pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
-    let mut char_iter = line.chars().enumerate().peekable();
-    let mut line_number = LineNumber(0);
-    let mut tokens: Vec<TokenAndPos> = Vec::new();
+/// Errors produced while lexing a single token. Unlike the old line-at-a-time
+/// tokenizer, a `LexError` does not stop the `Lexer`: it is yielded in place
+/// of the offending token and the `Lexer` resumes at the next whitespace
+/// boundary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnknownToken(Span, String),
+    /// `\q` for some `q` that isn't a recognized escape character.
+    InvalidEscape(Span, char),
+    /// `\xHH` or `\u{...}` whose digits didn't parse or named a value out
+    /// of range (e.g. a lone surrogate).
+    InvalidHexEscape(Span, String),
+    /// A raw, unescaped newline inside a string literal.
+    NewlineInString(Span),
+    /// End of input reached before a string literal's closing `"`.
+    UnterminatedString(Span),
+    /// A numeric literal that didn't parse: overflow, a malformed exponent,
+    /// a radix prefix with no digits after it, etc. Digit separators are
+    /// stripped wherever they appear and are never an error on their own.
+    InvalidNumber(Span, String),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexError::UnknownToken(span, ref token_str) => {
+                write!(f,
+                       "line {}, col {}: unimplemented token `{}`",
+                       span.start.line,
+                       span.start.column,
+                       token_str)
+            }
+            LexError::InvalidEscape(span, ch) => {
+                write!(f,
+                       "line {}, col {}: invalid escape `\\{}`",
+                       span.start.line,
+                       span.start.column,
+                       ch)
+            }
+            LexError::InvalidHexEscape(span, ref digits) => {
+                write!(f,
+                       "line {}, col {}: invalid escape value `{}`",
+                       span.start.line,
+                       span.start.column,
+                       digits)
+            }
+            LexError::NewlineInString(span) => {
+                write!(f,
+                       "line {}, col {}: newline in string literal",
+                       span.start.line,
+                       span.start.column)
+            }
+            LexError::UnterminatedString(span) => {
+                write!(f,
+                       "line {}, col {}: unterminated string",
+                       span.start.line,
+                       span.start.column)
+            }
+            LexError::InvalidNumber(span, ref raw) => {
+                write!(f,
+                       "line {}, col {}: invalid numeric literal `{}`",
+                       span.start.line,
+                       span.start.column,
+                       raw)
+            }
+        }
+    }
+}
+
+/// A streaming, recoverable tokenizer over a whole source string.
+///
+/// `Lexer` implements `Iterator<Item = Result<TokenAndPos, LexError>>`, so a
+/// parser can pull tokens lazily (wrap it in `Peekable` to look one token
+/// ahead) and a caller that wants every error in a program, rather than just
+/// the first one, can simply keep draining it: a malformed token yields an
+/// `Err` and scanning resumes right after it instead of aborting the rest of
+/// the source. It also tracks line/column as it goes so every token (and
+/// error) carries a `Span` of the exact source text it covers, which is what
+/// makes multi-line error messages like `line 40, col 12: ...` possible.
+pub struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: u32,
+    column: u32,
+    // A token produced as a side effect of scanning another one (e.g. the
+    // `Comment` that trails a `Rem`), held until the next call to `next`.
+    pending: Option<Result<TokenAndPos, LexError>>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            chars: source.chars().peekable(),
+            line: 1,
+            column: 0,
+            pending: None,
+        }
+    }
+
+    fn current_position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.chars.next();
+        if let Some(c) = ch {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 0;
+            } else {
+                self.column += 1;
+            }
+        }
+        ch
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
 
-    while char_iter.peek() != None {
-        let (pos, ch) = char_iter.next().unwrap();
-        let pos = pos as u32;
-
-        if pos == 0 {
-            if ch.is_numeric() {
-                let mut num_chars: Vec<char> = char_iter.by_ref()
-                    .take_while(|&(_, x)| !x.is_whitespace())
-                    .map(|(_, x)| x)
-                    .collect();
-                num_chars.insert(0, ch);
-                let num_str: String = num_chars.into_iter().collect();
-
-                match u32::from_str(num_str.as_str()) {
-                    Ok(number) => line_number = LineNumber(number),
-                    Err(_) => {
-                        return Err(format!("Line must start with number followed by \
-                                            whitespace:\n\t{}",
-                                           line))
+    fn skip_to_whitespace_boundary(&mut self) {
+        while let Some(ch) = self.peek() {
+            if ch.is_whitespace() {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    fn scan_string(&mut self, start: Position) -> Result<TokenAndPos, LexError> {
+        let mut bstring = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.bump();
+                    break;
+                }
+                Some('\n') => {
+                    let nl = self.current_position();
+                    return Err(LexError::NewlineInString(Span {
+                                                               start: nl,
+                                                               end: nl,
+                                                           }));
+                }
+                Some('\\') => {
+                    let esc_start = self.current_position();
+                    self.bump(); // the backslash
+                    match self.bump() {
+                        Some('n') => bstring.push('\n'),
+                        Some('t') => bstring.push('\t'),
+                        Some('r') => bstring.push('\r'),
+                        Some('\\') => bstring.push('\\'),
+                        Some('"') => bstring.push('"'),
+                        Some('0') => bstring.push('\0'),
+                        Some('x') => bstring.push(try!(self.scan_hex_escape(esc_start))),
+                        Some('u') => bstring.push(try!(self.scan_unicode_escape(esc_start))),
+                        Some(other) => {
+                            let span = Span {
+                                start: esc_start,
+                                end: self.current_position(),
+                            };
+                            return Err(LexError::InvalidEscape(span, other));
+                        }
+                        None => {
+                            let span = Span {
+                                start: start,
+                                end: self.current_position(),
+                            };
+                            return Err(LexError::UnterminatedString(span));
+                        }
                     }
+                }
+                Some(_) => bstring.push(self.bump().unwrap()),
+                None => {
+                    let span = Span {
+                        start: start,
+                        end: self.current_position(),
+                    };
+                    return Err(LexError::UnterminatedString(span));
+                }
+            }
+        }
+        let span = Span {
+            start: start,
+            end: self.current_position(),
+        };
+        Ok(TokenAndPos(span, Token::BString(bstring)))
+    }
+
+    /// Scans the two hex digits of a `\xHH` escape, having already consumed
+    /// the `\x`.
+    fn scan_hex_escape(&mut self, esc_start: Position) -> Result<char, LexError> {
+        let mut digits = String::new();
+        for _ in 0..2 {
+            match self.peek() {
+                Some(c) if c.is_digit(16) => digits.push(self.bump().unwrap()),
+                _ => {
+                    let span = Span {
+                        start: esc_start,
+                        end: self.current_position(),
+                    };
+                    return Err(LexError::InvalidHexEscape(span, digits));
+                }
+            }
+        }
+        match u8::from_str_radix(&digits, 16) {
+            Ok(byte) => Ok(byte as char),
+            Err(_) => {
+                let span = Span {
+                    start: esc_start,
+                    end: self.current_position(),
                 };
+                Err(LexError::InvalidHexEscape(span, digits))
+            }
+        }
+    }
+
+    /// Scans the `{...}` of a `\u{...}` escape, having already consumed the
+    /// `\u`.
+    fn scan_unicode_escape(&mut self, esc_start: Position) -> Result<char, LexError> {
+        if self.peek() != Some('{') {
+            let span = Span {
+                start: esc_start,
+                end: self.current_position(),
+            };
+            return Err(LexError::InvalidHexEscape(span, String::new()));
+        }
+        self.bump(); // the '{'
+
+        let mut digits = String::new();
+        loop {
+            match self.peek() {
+                Some('}') => {
+                    self.bump();
+                    break;
+                }
+                Some(c) if c.is_digit(16) => digits.push(self.bump().unwrap()),
+                _ => {
+                    let span = Span {
+                        start: esc_start,
+                        end: self.current_position(),
+                    };
+                    return Err(LexError::InvalidHexEscape(span, digits));
+                }
+            }
+        }
+
+        let value = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32);
+        match value {
+            Some(c) => Ok(c),
+            None => {
+                let span = Span {
+                    start: esc_start,
+                    end: self.current_position(),
+                };
+                Err(LexError::InvalidHexEscape(span, digits))
+            }
+        }
+    }
+
+    fn scan_comment(&mut self, start: Position) -> TokenAndPos {
+        let mut comment_str = String::new();
+        while let Some(ch) = self.peek() {
+            if ch == '\n' {
+                break;
+            }
+            comment_str.push(self.bump().unwrap());
+        }
+        let span = Span {
+            start: start,
+            end: self.current_position(),
+        };
+        TokenAndPos(span, Token::Comment(comment_str))
+    }
+
+    /// Scans a decimal/hex/octal/binary integer, or a decimal float, having
+    /// already consumed its first digit.
+    ///
+    /// `0x`/`0o`/`0b` select an alternate radix for an integer literal, and
+    /// an underscore may appear anywhere in the digit run as a visual
+    /// separator (e.g. `1_000_000`); both are stripped before parsing. A
+    /// `.` or `e`/`E` in a plain decimal run makes it a `Token::Float`
+    /// instead of a `Token::Number`.
+    fn scan_number(&mut self, start: Position, first: char) -> Result<TokenAndPos, LexError> {
+        if first == '0' {
+            let radix = match self.peek() {
+                Some('x') => Some(16),
+                Some('o') => Some(8),
+                Some('b') => Some(2),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                self.bump();
+                return self.scan_radix_number(start, radix);
+            }
+        }
+
+        let mut raw = String::new();
+        raw.push(first);
+        let mut is_float = false;
+        let mut seen_dot = false;
+        let mut seen_exponent = false;
+
+        while let Some(ch) = self.peek() {
+            if ch.is_digit(10) || ch == '_' {
+                raw.push(self.bump().unwrap());
+            } else if ch == '.' && !seen_dot && !seen_exponent {
+                is_float = true;
+                seen_dot = true;
+                raw.push(self.bump().unwrap());
+            } else if (ch == 'e' || ch == 'E') && !seen_exponent {
+                is_float = true;
+                seen_exponent = true;
+                raw.push(self.bump().unwrap());
+                match self.peek() {
+                    Some('+') | Some('-') => raw.push(self.bump().unwrap()),
+                    _ => (),
+                }
             } else {
-                return Err(format!("Line must start with a line number:\n\t{}", line));
+                break;
+            }
+        }
+
+        if let Some(ch) = self.peek() {
+            if ch.is_alphanumeric() || ch == '_' {
+                // A digit run glued directly to an identifier character
+                // (`10B`, `1_000x`) isn't a number and isn't a separate
+                // token either -- the rest of the lexer requires a
+                // whitespace/token boundary after every token, so enforce
+                // that here too instead of silently truncating the number.
+                while let Some(ch) = self.peek() {
+                    if ch.is_whitespace() {
+                        break;
+                    }
+                    raw.push(self.bump().unwrap());
+                }
+                let span = Span {
+                    start: start,
+                    end: self.current_position(),
+                };
+                return Err(LexError::InvalidNumber(span, raw));
+            }
+        }
+
+        let span = Span {
+            start: start,
+            end: self.current_position(),
+        };
+        let cleaned: String = raw.chars().filter(|&c| c != '_').collect();
+
+        if is_float {
+            match f64::from_str(&cleaned) {
+                Ok(f) => Ok(TokenAndPos(span, Token::Float(f))),
+                Err(_) => Err(LexError::InvalidNumber(span, raw)),
             }
         } else {
+            match i32::from_str(&cleaned) {
+                Ok(n) => Ok(TokenAndPos(span, Token::Number(n))),
+                Err(_) => Err(LexError::InvalidNumber(span, raw)),
+            }
+        }
+    }
+
+    fn scan_radix_number(&mut self, start: Position, radix: u32) -> Result<TokenAndPos, LexError> {
+        let prefix = match radix {
+            16 => "0x",
+            8 => "0o",
+            _ => "0b",
+        };
+        let mut digits = String::new();
+        while let Some(ch) = self.peek() {
+            if ch.is_digit(radix) || ch == '_' {
+                digits.push(self.bump().unwrap());
+            } else {
+                break;
+            }
+        }
+
+        if let Some(ch) = self.peek() {
+            if ch.is_alphanumeric() || ch == '_' {
+                // A digit run glued directly to an identifier character
+                // (`0x10g`, `0xAND`) isn't a number and isn't a separate
+                // token either -- the rest of the lexer requires a
+                // whitespace/token boundary after every token, so enforce
+                // that here too instead of silently truncating the number.
+                while let Some(ch) = self.peek() {
+                    if ch.is_whitespace() {
+                        break;
+                    }
+                    digits.push(self.bump().unwrap());
+                }
+                let span = Span {
+                    start: start,
+                    end: self.current_position(),
+                };
+                return Err(LexError::InvalidNumber(span, format!("{}{}", prefix, digits)));
+            }
+        }
+
+        let span = Span {
+            start: start,
+            end: self.current_position(),
+        };
+        let cleaned: String = digits.chars().filter(|&c| c != '_').collect();
+        match i32::from_str_radix(&cleaned, radix) {
+            Ok(n) => Ok(TokenAndPos(span, Token::Number(n))),
+            Err(_) => Err(LexError::InvalidNumber(span, format!("{}{}", prefix, digits))),
+        }
+    }
+
+    fn scan_other(&mut self, start: Position, first: char) -> Result<TokenAndPos, LexError> {
+        let mut token_str = String::new();
+        token_str.push(first);
+        while let Some(ch) = self.peek() {
             if ch.is_whitespace() {
-                // Skip whitespace
-                continue;
+                break;
             }
+            token_str.push(self.bump().unwrap());
+        }
+        let span = Span {
+            start: start,
+            end: self.current_position(),
+        };
 
-            // At the beginning of a string
-            if ch == '"' {
-                // TODO: Handle escaped quotes
-                // TODO: Handle malformed string
-                let str_chars: Vec<char> = char_iter.by_ref()
-                    .take_while(|&(_, x)| x != '"')
-                    .map(|(_, x)| x)
-                    .collect();
-                let bstring: String = str_chars.into_iter().collect();
-                tokens.push(TokenAndPos(pos, Token::BString(bstring)))
-            } else if ch == '-' {
-                if !tokens.is_empty() && tokens.last().unwrap().1.is_value() {
-                    tokens.push(TokenAndPos(pos, Token::Minus))
-                } else {
-                    tokens.push(TokenAndPos(pos, Token::UMinus))
+        match Token::token_for_string(token_str.as_str()) {
+            Some(Token::Rem) => {
+                // Skip the single space separating REM from its comment
+                // body, if there is one -- a bare REM (end of line, or
+                // end of input) has no body to separate from, and must
+                // not swallow the newline that ends it.
+                if let Some(' ') | Some('\t') = self.peek() {
+                    self.bump();
                 }
-            } else if ch == '!' {
-                // Unary operators aren't necessarily separated by whitespace
-                tokens.push(TokenAndPos(pos, Token::Bang))
-            } else if ch == '(' {
-                tokens.push(TokenAndPos(pos, Token::LParen))
-            } else if ch == ')' {
-                tokens.push(TokenAndPos(pos, Token::RParen))
-            } else {
-                // Otherwise, next token is until next whitespace
-                let mut token_chars: Vec<char> = char_iter.by_ref()
-                    .peeking_take_while(|&(_, x)| !x.is_whitespace() || x == ')')
-                    .map(|(_, x)| x)
-                    .collect();
-                token_chars.insert(0, ch);
-                let token_str: String = token_chars.into_iter().collect();
-
-                if i32::from_str(token_str.as_str()).is_ok() {
-                    tokens.push(TokenAndPos(pos,
-                                            Token::Number(i32::from_str(token_str.as_str())
-                                                .unwrap())));
-                } else {
-                    let token = Token::token_for_string(token_str.as_str());
-
-                    match token {
-                        None =>  {
-                            if is_valid_identifier(&token_str) {
-                                tokens.push(TokenAndPos(pos, Token::Variable(token_str.to_string())))
-                            } else {
-                                return Err(format!("Unimplemented token at {}:\t{}", pos, token_str))
-                            }
-                        }
-                        Some(Token::Rem) => {
-                            tokens.push(TokenAndPos(pos, Token::Rem));
-                            // Skip the space after REM
-                            char_iter.next();
-                            // The rest of the line is a comment
-                            let comment_str: String = char_iter.by_ref().map(|(_, x)| x).collect();
-                            tokens.push(TokenAndPos((pos + 4) as u32, Token::Comment(comment_str)))
-                        }
+                let comment_start = self.current_position();
+                self.pending = Some(Ok(self.scan_comment(comment_start)));
+                Ok(TokenAndPos(span, Token::Rem))
+            }
+            Some(token) => Ok(TokenAndPos(span, token)),
+            None => {
+                match parse_variable(&token_str) {
+                    Some((name, kind)) => Ok(TokenAndPos(span, Token::Variable(name, kind))),
+                    None => Err(LexError::UnknownToken(span, token_str)),
+                }
+            }
+        }
+    }
+}
 
-                        Some(token) => {
-                            tokens.push(TokenAndPos(pos, token));
-                        }
-                   }
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<TokenAndPos, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pending) = self.pending.take() {
+            return Some(pending);
+        }
+
+        let (start, ch) = loop {
+            let start = self.current_position();
+            match self.bump() {
+                Some(ch) => {
+                    if ch.is_whitespace() {
+                        continue;
+                    }
+                    break (start, ch);
                 }
+                None => return None,
             }
+        };
+
+        let result = if ch == '"' {
+            self.scan_string(start)
+        } else if ch == '-' {
+            Ok(TokenAndPos(Span {
+                                start: start,
+                                end: self.current_position(),
+                            },
+                           Token::Minus))
+        } else if ch == '!' {
+            Ok(TokenAndPos(Span {
+                                start: start,
+                                end: self.current_position(),
+                            },
+                           Token::Bang))
+        } else if ch == '(' {
+            Ok(TokenAndPos(Span {
+                                start: start,
+                                end: self.current_position(),
+                            },
+                           Token::LParen))
+        } else if ch == ')' {
+            Ok(TokenAndPos(Span {
+                                start: start,
+                                end: self.current_position(),
+                            },
+                           Token::RParen))
+        } else if ch.is_digit(10) {
+            self.scan_number(start, ch)
+        } else {
+            self.scan_other(start, ch)
+        };
+
+        if result.is_err() {
+            self.skip_to_whitespace_boundary();
+        }
+
+        Some(result)
+    }
+}
+
+/// Lexes a single BASIC line, including its leading line number.
+///
+/// This is a thin wrapper over `Lexer` kept for callers (and tests) that
+/// still want a whole line tokenized eagerly: it drains the iterator,
+/// disambiguates `Token::Minus` from unary minus the same way the old
+/// line-at-a-time tokenizer did, and bails on the first error rather than
+/// collecting every one -- use `Lexer` directly if you want to keep going
+/// past a malformed token.
+pub fn tokenize_line(line: &str) -> Result<LineOfCode, String> {
+    let mut lexer = Lexer::new(line).peekable();
+
+    let line_number = match lexer.next() {
+        Some(Ok(TokenAndPos(_, Token::Number(n)))) if n >= 0 => LineNumber(n as u32),
+        Some(Ok(TokenAndPos(span, _))) => {
+            return Err(format!("Line must start with a line number at line {}, col {}:\n\t{}",
+                                span.start.line,
+                                span.start.column,
+                                line))
+        }
+        Some(Err(e)) => return Err(format!("{}:\n\t{}", e, line)),
+        None => return Err(format!("Line must start with a line number:\n\t{}", line)),
+    };
+
+    let mut tokens: Vec<TokenAndPos> = Vec::new();
+    for result in lexer {
+        match result {
+            Ok(TokenAndPos(span, Token::Minus)) => {
+                // Unary minus isn't distinguishable until we know what precedes it.
+                if tokens.is_empty() || !tokens.last().unwrap().1.is_value() {
+                    tokens.push(TokenAndPos(span, Token::UMinus));
+                } else {
+                    tokens.push(TokenAndPos(span, Token::Minus));
+                }
+            }
+            Ok(token_and_pos) => tokens.push(token_and_pos),
+            Err(e) => return Err(format!("{}:\n\t{}", e, line)),
         }
     }
 
@@ -239,9 +736,43 @@ fn is_valid_identifier(token_str: &str) -> bool {
     true
 }
 
+// An identifier, optionally followed by a single `$` (string) or `%`
+// (integer) type sigil. A bare `$`/`%` with no identifier in front of it is
+// rejected, same as any other invalid identifier.
+fn parse_variable(token_str: &str) -> Option<(String, VarKind)> {
+    let (name, kind) = if token_str.ends_with('$') {
+        (&token_str[..token_str.len() - 1], VarKind::Str)
+    } else if token_str.ends_with('%') {
+        (&token_str[..token_str.len() - 1], VarKind::Int)
+    } else {
+        (token_str, VarKind::Number)
+    };
+
+    if is_valid_identifier(name) {
+        Some((name.to_string(), kind))
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use lexer::*;
+
+    fn pos(line: u32, column: u32) -> Position {
+        Position {
+            line: line,
+            column: column,
+        }
+    }
+
+    fn span(start: Position, end: Position) -> Span {
+        Span {
+            start: start,
+            end: end,
+        }
+    }
+
     #[test]
     fn tokenize_no_line_number() {
         let line_of_code = tokenize_line("REM Invalid Line");
@@ -254,12 +785,19 @@ mod tests {
         assert!(line_of_code.is_err());
     }
 
+    #[test]
+    fn tokenize_overflowing_line_number() {
+        let line_of_code = tokenize_line("99999999999999999999 REM Invalid Line");
+        assert!(line_of_code.is_err());
+    }
+
     #[test]
     fn tokenize_line_with_goto() {
         let line_of_code = tokenize_line("10 GOTO 100").unwrap();
         assert_eq!(LineNumber(10), line_of_code.line_number);
-        let tokens: Vec<TokenAndPos> = vec![TokenAndPos(3, Token::Goto),
-                                            TokenAndPos(8, Token::Number(100))];
+        let tokens: Vec<TokenAndPos> =
+            vec![TokenAndPos(span(pos(1, 3), pos(1, 7)), Token::Goto),
+                 TokenAndPos(span(pos(1, 8), pos(1, 11)), Token::Number(100))];
         assert_eq!(tokens, line_of_code.tokens)
     }
 
@@ -268,8 +806,9 @@ mod tests {
         let line_of_code = tokenize_line("10 PRINT \"FOO BAR BAZ\"").unwrap();
         assert_eq!(LineNumber(10), line_of_code.line_number);
         let tokens: Vec<TokenAndPos> =
-            vec![TokenAndPos(3, Token::Print),
-                 TokenAndPos(9, Token::BString("FOO BAR BAZ".to_string()))];
+            vec![TokenAndPos(span(pos(1, 3), pos(1, 8)), Token::Print),
+                 TokenAndPos(span(pos(1, 9), pos(1, 22)),
+                             Token::BString("FOO BAR BAZ".to_string()))];
         assert_eq!(tokens, line_of_code.tokens)
     }
 
@@ -277,8 +816,10 @@ mod tests {
     fn tokenize_line_with_identifier() {
         let line_of_code = tokenize_line("10 INPUT A").unwrap();
         assert_eq!(LineNumber(10), line_of_code.line_number);
-        let tokens: Vec<TokenAndPos> = vec![TokenAndPos(3, Token::Input),
-                                            TokenAndPos(9, Token::Variable("A".to_string()))];
+        let tokens: Vec<TokenAndPos> =
+            vec![TokenAndPos(span(pos(1, 3), pos(1, 8)), Token::Input),
+                 TokenAndPos(span(pos(1, 9), pos(1, 10)),
+                             Token::Variable("A".to_string(), VarKind::Number))];
         assert_eq!(tokens, line_of_code.tokens)
     }
 
@@ -293,8 +834,219 @@ mod tests {
         let line_of_code = tokenize_line("5  REM THIS IS A COMMENT 123").unwrap();
         assert_eq!(LineNumber(5), line_of_code.line_number);
         let tokens: Vec<TokenAndPos> =
-            vec![TokenAndPos(3, Token::Rem),
-                 TokenAndPos(7, Token::Comment("THIS IS A COMMENT 123".to_string()))];
+            vec![TokenAndPos(span(pos(1, 3), pos(1, 6)), Token::Rem),
+                 TokenAndPos(span(pos(1, 7), pos(1, 28)),
+                             Token::Comment("THIS IS A COMMENT 123".to_string()))];
         assert_eq!(tokens, line_of_code.tokens)
     }
+
+    #[test]
+    fn lexer_collects_multiple_errors_instead_of_stopping() {
+        let lexer = Lexer::new("10 ` 20 ~ 30");
+        let results: Vec<Result<TokenAndPos, LexError>> = lexer.collect();
+        // The line number (10), a malformed token (`` ` ``), 20, another
+        // malformed token (`~`), and the trailing number (30) should all
+        // come back instead of bailing at the first bad token.
+        assert_eq!(5, results.len());
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        assert!(results[3].is_err());
+        assert!(results[4].is_ok());
+    }
+
+    #[test]
+    fn lexer_is_peekable_for_lookahead() {
+        let mut lexer = Lexer::new("10 GOTO 100").peekable();
+        assert_eq!(Some(&Ok(TokenAndPos(span(pos(1, 0), pos(1, 2)), Token::Number(10)))),
+                   lexer.peek());
+        assert_eq!(Some(Ok(TokenAndPos(span(pos(1, 0), pos(1, 2)), Token::Number(10)))),
+                   lexer.next());
+        assert_eq!(Some(&Ok(TokenAndPos(span(pos(1, 3), pos(1, 7)), Token::Goto))),
+                   lexer.peek());
+    }
+
+    #[test]
+    fn lexer_tracks_lines_across_newlines() {
+        let mut lexer = Lexer::new("10 GOTO 100\n20 PRINT \"HI\"");
+        let goto_line = lexer.next().unwrap().unwrap();
+        assert_eq!(1, goto_line.0.start.line);
+        let tokens: Vec<TokenAndPos> = lexer.collect::<Result<_, _>>().unwrap();
+        let print_token = &tokens[3];
+        assert_eq!(Token::Print, print_token.1);
+        assert_eq!(2, print_token.0.start.line);
+        assert_eq!(3, print_token.0.start.column);
+    }
+
+    #[test]
+    fn tokenize_line_with_escaped_quote() {
+        let line_of_code = tokenize_line("10 PRINT \"a\\\"b\"").unwrap();
+        let tokens: Vec<TokenAndPos> =
+            vec![TokenAndPos(span(pos(1, 3), pos(1, 8)), Token::Print),
+                 TokenAndPos(span(pos(1, 9), pos(1, 15)), Token::BString("a\"b".to_string()))];
+        assert_eq!(tokens, line_of_code.tokens)
+    }
+
+    #[test]
+    fn tokenize_line_with_common_escapes() {
+        let line_of_code = tokenize_line("10 PRINT \"a\\nb\\tc\\\\d\"").unwrap();
+        assert_eq!(Token::BString("a\nb\tc\\d".to_string()), line_of_code.tokens[1].1);
+    }
+
+    #[test]
+    fn tokenize_line_with_hex_escape() {
+        let line_of_code = tokenize_line("10 PRINT \"\\x41\"").unwrap();
+        assert_eq!(Token::BString("A".to_string()), line_of_code.tokens[1].1);
+    }
+
+    #[test]
+    fn tokenize_line_with_unicode_escape() {
+        let line_of_code = tokenize_line("10 PRINT \"\\u{1F600}\"").unwrap();
+        assert_eq!(Token::BString("\u{1F600}".to_string()), line_of_code.tokens[1].1);
+    }
+
+    #[test]
+    fn tokenize_line_with_invalid_escape() {
+        let mut lexer = Lexer::new("10 PRINT \"a\\qb\"");
+        let results: Vec<Result<TokenAndPos, LexError>> = lexer.by_ref().collect();
+        let found = results.iter()
+            .any(|r| match *r {
+                     Err(LexError::InvalidEscape(_, 'q')) => true,
+                     _ => false,
+                 });
+        assert!(found);
+    }
+
+    #[test]
+    fn tokenize_line_with_unterminated_string() {
+        let line_of_code = tokenize_line("10 PRINT \"unterminated");
+        assert!(line_of_code.is_err());
+    }
+
+    #[test]
+    fn tokenize_line_with_newline_in_string() {
+        let mut lexer = Lexer::new("10 PRINT \"oops\nmore");
+        let results: Vec<Result<TokenAndPos, LexError>> = lexer.by_ref().collect();
+        let found = results.iter()
+            .any(|r| match *r {
+                     Err(LexError::NewlineInString(_)) => true,
+                     _ => false,
+                 });
+        assert!(found);
+    }
+
+    #[test]
+    fn tokenize_line_with_float() {
+        let line_of_code = tokenize_line("10 LET X = 3.14").unwrap();
+        assert_eq!(Token::Float(3.14), *last_token(&line_of_code));
+    }
+
+    #[test]
+    fn tokenize_line_with_exponent() {
+        let line_of_code = tokenize_line("10 LET X = 1.0e-3").unwrap();
+        assert_eq!(Token::Float(1.0e-3), *last_token(&line_of_code));
+    }
+
+    #[test]
+    fn tokenize_line_with_hex_integer() {
+        let line_of_code = tokenize_line("10 LET X = 0xFF_FF").unwrap();
+        assert_eq!(Token::Number(0xFFFF), *last_token(&line_of_code));
+    }
+
+    #[test]
+    fn tokenize_line_with_octal_and_binary_integers() {
+        let line_of_code = tokenize_line("10 LET X = 0o17").unwrap();
+        assert_eq!(Token::Number(0o17), *last_token(&line_of_code));
+
+        let line_of_code = tokenize_line("10 LET X = 0b101").unwrap();
+        assert_eq!(Token::Number(0b101), *last_token(&line_of_code));
+    }
+
+    #[test]
+    fn tokenize_line_with_digit_separators() {
+        let line_of_code = tokenize_line("10 LET X = 1_000_000").unwrap();
+        assert_eq!(Token::Number(1_000_000), *last_token(&line_of_code));
+    }
+
+    #[test]
+    fn tokenize_line_with_malformed_exponent() {
+        let mut lexer = Lexer::new("10 LET X = 1.0e");
+        let results: Vec<Result<TokenAndPos, LexError>> = lexer.by_ref().collect();
+        let found = results.iter()
+            .any(|r| match *r {
+                     Err(LexError::InvalidNumber(_, _)) => true,
+                     _ => false,
+                 });
+        assert!(found);
+    }
+
+    fn last_token(line_of_code: &LineOfCode) -> &Token {
+        &line_of_code.tokens.last().unwrap().1
+    }
+
+    #[test]
+    fn tokenize_line_with_boolean_condition() {
+        let line_of_code = tokenize_line("10 IF X > 0 AND X < 10 THEN GOTO 100").unwrap();
+        let tokens: Vec<Token> = line_of_code.tokens.into_iter().map(|t| t.1).collect();
+        assert_eq!(vec![Token::If,
+                        Token::Variable("X".to_string(), VarKind::Number),
+                        Token::GreaterThan,
+                        Token::Number(0),
+                        Token::And,
+                        Token::Variable("X".to_string(), VarKind::Number),
+                        Token::LessThan,
+                        Token::Number(10),
+                        Token::Then,
+                        Token::Goto,
+                        Token::Number(100)],
+                   tokens);
+    }
+
+    #[test]
+    fn tokenize_line_with_mod_and_not() {
+        let line_of_code = tokenize_line("10 IF NOT X MOD 2 THEN GOTO 100").unwrap();
+        let tokens: Vec<Token> = line_of_code.tokens.into_iter().map(|t| t.1).collect();
+        assert_eq!(vec![Token::If,
+                        Token::Not,
+                        Token::Variable("X".to_string(), VarKind::Number),
+                        Token::Mod,
+                        Token::Number(2),
+                        Token::Then,
+                        Token::Goto,
+                        Token::Number(100)],
+                   tokens);
+    }
+
+    #[test]
+    fn operator_precedence_layers_or_below_and_below_relational() {
+        assert!(Token::Or.operator_precedence().unwrap() <
+                Token::And.operator_precedence().unwrap());
+        assert!(Token::And.operator_precedence().unwrap() <
+                Token::Equals.operator_precedence().unwrap());
+        assert!(Token::Equals.operator_precedence().unwrap() <
+                Token::Plus.operator_precedence().unwrap());
+        assert!(Token::Plus.operator_precedence().unwrap() <
+                Token::Mod.operator_precedence().unwrap());
+        assert!(Token::Not.operator_precedence().is_err());
+    }
+
+    #[test]
+    fn tokenize_line_with_string_sigil() {
+        let line_of_code = tokenize_line("10 INPUT A$").unwrap();
+        assert_eq!(Token::Variable("A".to_string(), VarKind::Str),
+                   line_of_code.tokens[1].1);
+    }
+
+    #[test]
+    fn tokenize_line_with_int_sigil() {
+        let line_of_code = tokenize_line("10 LET N% = 5").unwrap();
+        assert_eq!(Token::Variable("N".to_string(), VarKind::Int),
+                   line_of_code.tokens[1].1);
+    }
+
+    #[test]
+    fn tokenize_line_with_bare_sigil() {
+        let line_of_code = tokenize_line("10 INPUT $");
+        assert!(line_of_code.is_err());
+    }
 }